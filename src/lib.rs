@@ -1,5 +1,6 @@
 use std::borrow::Cow;
 use std::ffi::CStr;
+use std::io::{Read, Write};
 use std::os::raw::c_void;
 use std::sync::Mutex;
 
@@ -14,9 +15,11 @@ use varnish::vcl::{
 
 run_vtc_tests!("tests/*.vtc");
 
-/// General note: all functions in this vmod will silently fail if given an invalid
-/// regex, which means that `.is_match()` and `.capture()` will always return false,
-/// and replace will be a noop.
+/// General note: by default, all functions in this vmod will silently fail if given an
+/// invalid regex, which means that `.is_match()` and `.capture()` will always return false,
+/// and replace will be a noop. Pass `strict = true` to `init::new()` to turn that into a
+/// `ctx.fail()` that aborts the VCL transaction instead; see `init::error()` to check a
+/// pattern's validity without triggering either behavior.
 #[varnish::vmod(docs = "API.md")]
 mod rers {
     use std::error::Error;
@@ -29,7 +32,7 @@ mod rers {
     use varnish::ffi::{self, vdp, vfp};
     use varnish::vcl::{new_vdp, new_vfp, Ctx, Event};
 
-    use super::{clamp_i64_to_usize, init, Captures, Direction, Vxp};
+    use super::{clamp_i64_to_usize, init, Captures, Direction, Splits, Vxp};
 
     impl init {
         /// Build a regex store, optionally specifying its size `n` (defaults to 1000). The
@@ -37,18 +40,49 @@ mod rers {
         /// that wouldn't fit in it, it will remove the Least Recently Used regex to make
         /// space for the newcomer.
         /// `n` will be clamped between 1 and `usize::MAX`.
+        ///
+        /// `window` sets the default bounded-memory match window (in bytes) used by
+        /// `replace_resp_body()` when streaming a body: it's the number of trailing bytes
+        /// kept in reserve in case a match straddles a chunk boundary. Defaults to 64KiB and
+        /// can be overridden per-call.
+        /// `strict`, when set, turns an invalid `res` regex from a silent "no match"/no-op
+        /// into a `ctx.fail()` that aborts the VCL transaction. Defaults to `false`, keeping
+        /// the lenient behavior described above.
         #[must_use]
-        pub fn new(#[default(1000)] cache_size: i64) -> Self {
+        pub fn new(
+            #[default(1000)] cache_size: i64,
+            #[default(65536)] window: i64,
+            #[default(false)] strict: bool,
+        ) -> Self {
             let cap =
                 NonZeroUsize::new(clamp_i64_to_usize(cache_size)).unwrap_or(NonZeroUsize::MIN);
             init {
                 mutexed_cache: Mutex::new(LruCache::new(cap)),
+                window: clamp_i64_to_usize(window).max(1),
+                strict,
+            }
+        }
+
+        /// Compile `res` into the regex cache ahead of time. Meant to be called from
+        /// `vcl_init`, so that a syntax error in a pattern used later on is caught at load
+        /// time instead of on first use; combine with the `strict` option of `new()` to abort
+        /// loading on error, or with `error()` to inspect it.
+        pub fn compile(&self, ctx: &mut Ctx, res: &str) {
+            let _ = self.get_regex(ctx, res);
+        }
+
+        /// Return the compile error for `res`, or an empty string if the pattern is valid.
+        /// Lets VCL branch on regex validity without triggering `strict` failure behavior.
+        pub fn error(&self, res: &str) -> String {
+            match self.compile_cached(res) {
+                Ok(_) => String::new(),
+                Err(e) => e,
             }
         }
 
         /// Return `true` if `regex` matches on `s`
-        pub fn is_match(&self, s: &str, res: &str) -> bool {
-            self.get_regex(res)
+        pub fn is_match(&self, ctx: &mut Ctx, s: &str, res: &str) -> bool {
+            self.get_regex(ctx, res)
                 .map(|re| re.is_match(s.as_bytes()))
                 .unwrap_or(false)
         }
@@ -57,12 +91,13 @@ mod rers {
         /// only the first `lim` groups are replaced.
         pub fn replace(
             &self,
+            ctx: &mut Ctx,
             haystack: &str,
             res: &str,
             sub: &str,
             #[default(0)] limit: i64,
         ) -> Result<String, String> {
-            let re = self.get_regex(res)?;
+            let re = self.get_regex(ctx, res)?;
             let repl = re.replacen(
                 haystack.as_bytes(),
                 clamp_i64_to_usize(limit),
@@ -81,7 +116,7 @@ mod rers {
             #[shared_per_task] vp: &mut Option<Box<Captures<'_>>>,
             res: &str,
         ) -> Result<bool, Box<dyn Error>> {
-            let Ok(re) = self.get_regex(res) else {
+            let Ok(re) = self.get_regex(ctx, res) else {
                 return Ok(false);
             };
 
@@ -119,11 +154,12 @@ mod rers {
         /// function will simply return `false`.
         pub fn capture<'a>(
             &self,
+            ctx: &mut Ctx,
             #[shared_per_task] vp: &mut Option<Box<Captures<'a>>>,
             s: &'a str,
             res: &str,
         ) -> bool {
-            let Ok(re) = self.get_regex(res) else {
+            let Ok(re) = self.get_regex(ctx, res) else {
                 return false;
             };
 
@@ -166,42 +202,201 @@ mod rers {
                 .map(|m| m.as_bytes())
         }
 
+        /// Split `s` on every match of `regex`, stashing the resulting pieces so they can be
+        /// read back one at a time with `split_get()` (or counted with `split_count()`). See
+        /// `splitn()` for a variant bounded to a maximum number of pieces.
+        pub fn split<'a>(
+            &self,
+            ctx: &mut Ctx,
+            #[shared_per_task] vp: &mut Option<Box<Splits<'a>>>,
+            s: &'a str,
+            res: &str,
+        ) -> bool {
+            let Ok(re) = self.get_regex(ctx, res) else {
+                return false;
+            };
+            *vp = Some(Box::new(Splits {
+                pieces: re.split(s.as_bytes()).collect(),
+                text: None,
+            }));
+            true
+        }
+
+        /// Same as `split()`, but stops after producing `limit` pieces, with the last one
+        /// holding everything left over in `s`. `limit <= 0` means unbounded, same as
+        /// `split()` (regex's own `splitn(.., 0)` would instead produce zero pieces).
+        pub fn splitn<'a>(
+            &self,
+            ctx: &mut Ctx,
+            #[shared_per_task] vp: &mut Option<Box<Splits<'a>>>,
+            s: &'a str,
+            res: &str,
+            limit: i64,
+        ) -> bool {
+            let Ok(re) = self.get_regex(ctx, res) else {
+                return false;
+            };
+            let pieces = if limit <= 0 {
+                re.split(s.as_bytes()).collect()
+            } else {
+                re.splitn(s.as_bytes(), clamp_i64_to_usize(limit)).collect()
+            };
+            *vp = Some(Box::new(Splits { pieces, text: None }));
+            true
+        }
+
+        /// Same as `split()` but works on the request body. The request must have been
+        /// cached first (using `std.cache_req_body()` for example) or the call will fail and
+        /// interrupt the VCL transaction. The body is split as raw bytes, whether or not it is
+        /// valid utf8; the function only returns `false` if `res` doesn't compile.
+        pub fn split_req_body(
+            &self,
+            ctx: &mut Ctx,
+            #[shared_per_task] vp: &mut Option<Box<Splits<'_>>>,
+            res: &str,
+        ) -> Result<bool, Box<dyn Error>> {
+            let Ok(re) = self.get_regex(ctx, res) else {
+                return Ok(false);
+            };
+
+            // same coalescing trick as `capture_req_body()`
+            let body = ctx
+                .cached_req_body()?
+                .into_iter()
+                .fold(Vec::new(), |mut v, b| {
+                    v.extend_from_slice(b);
+                    v
+                });
+
+            // same lifetime trick as `capture_req_body()`: we need rust to trust us on the
+            // lifetime of slice (which pieces will point to)
+            let ptr = body.as_ptr();
+            let len = body.len();
+            let slice = unsafe { slice::from_raw_parts(ptr, len) };
+            *vp = Some(Box::new(Splits {
+                pieces: re.split(slice).collect(),
+                text: Some(body),
+            }));
+            Ok(true)
+        }
+
+        /// Return the `n`th piece produced by `split()`, `splitn()` or `split_req_body()`.
+        /// Trying to access a non-existing piece will return `None`.
+        #[allow(clippy::unused_self)] // TODO: figure out why &self is not being used
+        pub fn split_get<'a>(
+            &self,
+            #[shared_per_task] vp: &mut Option<Box<Splits<'a>>>,
+            n: i64,
+        ) -> Option<&'a [u8]> {
+            vp.as_ref()
+                .and_then(|s| s.pieces.get(clamp_i64_to_usize(n)))
+                .copied()
+        }
+
+        /// Return the number of pieces produced by `split()`, `splitn()` or
+        /// `split_req_body()`, or `0` if none of those has been called yet.
+        #[allow(clippy::unused_self)] // TODO: figure out why &self is not being used
+        pub fn split_count(&self, #[shared_per_task] vp: &mut Option<Box<Splits<'_>>>) -> i64 {
+            vp.as_ref().map_or(0, |s| s.pieces.len() as i64)
+        }
+
         /// Add a regex/substitute pair to use when delivering the response body to a
         /// client, or ingesting a body from the backend.
         /// Note that you will need to include `rers` in `resp.filters` for it to
         /// have an effect. This function can be called multiple times, with each pair being
         /// called sequentially.
+        ///
+        /// The body is normally streamed in bounded-memory chunks: `window` (defaulting to
+        /// the value set in `init::new()`) controls how many trailing bytes are kept around
+        /// across chunks in case a match straddles the boundary. Patterns that depend on
+        /// buffer-relative anchors (`^`, `$`, `\A`, `\z`, or the `(?m)` flag) can match
+        /// differently depending on where a chunk is cut, so those automatically fall back
+        /// to buffering the whole body before replacing, same as before this option existed.
+        /// Registering more than one `res`/`sub` pair for the same direction also falls back
+        /// to full buffering, since chaining streamed steps can lose a match that only forms
+        /// across an earlier step's emitted/retained boundary. A non-zero `limit` does too:
+        /// streaming applies a step to one emitted chunk at a time, so bounding it to `limit`
+        /// only makes sense over the whole body.
+        ///
+        /// If the body carries a `Content-Encoding` of `gzip`, `deflate` or `br`, it is
+        /// transparently decompressed before the regex runs and re-compressed with the same
+        /// codec afterwards, so `res`/`sub` always operate on plain text. An unrecognized or
+        /// combined (e.g. `gzip, br`) encoding is skipped (the body is left untouched and a
+        /// warning is logged) unless `fail_on_bad_encoding` is set, in which case the
+        /// transaction is aborted instead.
         pub fn replace_resp_body(
             &self,
             ctx: &mut Ctx,
             res: &str,
             sub: &str,
             #[default(0)] limit: i64,
+            #[default(0)] window: i64,
+            #[default(false)] fail_on_bad_encoding: bool,
         ) {
             let direction = if ctx.http_req.is_some() {
                 Direction::Deliver
             } else {
                 Direction::Fetch
             };
-            self.replace_body(ctx, res, sub, limit, direction);
-        }
-
-        //        /// Add a regex/substitute pair to use when ingesting the response body from a
-        //        /// client, or delivering a body from the backend.
-        //        /// Note that you will need to include `rers` in `resp.filters` for it to
-        //        /// have an effect. This function can be called multiple times, with each pair being
-        //        /// called sequentially.
-        //        pub fn replace_req_body(&self, ctx: &mut Ctx, res: &str, sub: &str,
-        //            #[default(0)]
-        //            limit: i64,
-        //            ) {
-        //            let direction = if ctx.http_req.is_some() {
-        //                Direction::Fetch
-        //            } else {
-        //                Direction::Deliver
-        //            };
-        //            self.replace_body(ctx, res,sub, limit, direction)
-        //        }
+            let window = if window <= 0 {
+                self.window
+            } else {
+                clamp_i64_to_usize(window)
+            };
+            self.replace_body(
+                ctx,
+                res,
+                sub,
+                limit,
+                direction,
+                window,
+                fail_on_bad_encoding,
+            );
+        }
+
+        /// Add a regex/substitute pair to use when sending the request body to the backend,
+        /// or ingesting a body sent by the client.
+        /// Note that you will need to include `rers` in `bereq.filters` (to rewrite the body
+        /// on its way to the backend) or `req.filters` (to rewrite it as it's read from the
+        /// client) for it to have an effect. This function can be called multiple times,
+        /// with each pair being called sequentially.
+        ///
+        /// Bounded-memory streaming, anchor detection and `Content-Encoding` handling all
+        /// work the same way as for `replace_resp_body()`.
+        ///
+        /// If `std.cache_req_body()` has also been called, it caches the body as seen by
+        /// Varnish *before* any of these pairs run: they're applied once, while the body is
+        /// streamed out to the backend, and don't re-run against that cached copy, so there's
+        /// no double substitution between the two.
+        pub fn replace_req_body(
+            &self,
+            ctx: &mut Ctx,
+            res: &str,
+            sub: &str,
+            #[default(0)] limit: i64,
+            #[default(0)] window: i64,
+            #[default(false)] fail_on_bad_encoding: bool,
+        ) {
+            let direction = if ctx.http_req.is_some() {
+                Direction::ReqFetch
+            } else {
+                Direction::ReqDeliver
+            };
+            let window = if window <= 0 {
+                self.window
+            } else {
+                clamp_i64_to_usize(window)
+            };
+            self.replace_body(
+                ctx,
+                res,
+                sub,
+                limit,
+                direction,
+                window,
+                fail_on_bad_encoding,
+            );
+        }
     }
 
     #[event]
@@ -222,7 +417,9 @@ mod rers {
 }
 
 impl init {
-    fn get_regex(&self, res: &str) -> Result<Regex, String> {
+    /// Compile (or fetch from cache) the regex for `res`, without any logging or `strict`
+    /// failure behavior. Used by `error()`, which needs the raw result to hand back to VCL.
+    fn compile_cached(&self, res: &str) -> Result<Regex, String> {
         let mut lru = self.mutexed_cache.lock().unwrap();
         if lru.get(res).is_none() {
             let comp = Regex::new(res).map_err(|e| e.to_string());
@@ -230,15 +427,36 @@ impl init {
         }
         lru.get(res).unwrap().clone()
     }
-    fn replace_body(&self, ctx: &mut Ctx, res: &str, sub: &str, limit: i64, dir: Direction) {
-        let Ok(re) = self
-            .get_regex(res)
-            .map_err(|e| ctx.log(LogTag::VclError, &e))
-        else {
-            return; // FIXME: should this return an error to call VRT_fail()?
+
+    /// Same as `compile_cached()`, but logs a syntax error through the VCL log and, when
+    /// `strict` is set, calls `ctx.fail()` to abort the transaction instead of letting the
+    /// caller silently treat it as "no match".
+    fn get_regex(&self, ctx: &mut Ctx, res: &str) -> Result<Regex, String> {
+        let result = self.compile_cached(res);
+        if let Err(ref e) = result {
+            ctx.log(LogTag::VclError, e);
+            if self.strict {
+                ctx.fail(e);
+            }
+        }
+        result
+    }
+
+    fn replace_body(
+        &self,
+        ctx: &mut Ctx,
+        res: &str,
+        sub: &str,
+        limit: i64,
+        dir: Direction,
+        window: usize,
+        fail_on_bad_encoding: bool,
+    ) {
+        let Ok(re) = self.get_regex(ctx, res) else {
+            return; // get_regex already logged and, if `strict`, failed the transaction
         };
 
-        let priv_opt = unsafe { ffi::VRT_priv_task(ctx.raw, PRIV_ANCHOR).as_mut() };
+        let priv_opt = unsafe { ffi::VRT_priv_task(ctx.raw, priv_anchor_for(dir)).as_mut() };
         let Some(priv_opt) = priv_opt else {
             ctx.fail("rers: couldn't retrieve priv_task (workspace too small?)");
             return;
@@ -246,15 +464,28 @@ impl init {
 
         // Low level access: convert pointer into a Box, manipulate it, and store it back
         let vp = unsafe { (*priv_opt).take::<Vxp>() };
-        let value = (dir, re, sub.to_owned(), clamp_i64_to_usize(limit));
+        let step = Step {
+            dir,
+            buffer_full: pattern_needs_full_buffer(res),
+            re,
+            sub: sub.to_owned(),
+            limit: clamp_i64_to_usize(limit),
+            window,
+            fail_on_bad_encoding,
+        };
         let ri = if let Some(mut ri) = vp {
-            ri.steps.push(value);
+            ri.steps.push(step);
             ri
         } else {
             Box::new(Vxp {
+                steps: vec![step],
                 body: Vec::new(),
-                steps: vec![value],
+                carry: Vec::new(),
                 sent: None,
+                out_sent: 0,
+                finished: false,
+                encoding: Encoding::Identity,
+                req_body: false,
             })
         };
         unsafe {
@@ -266,11 +497,27 @@ impl init {
 #[allow(non_camel_case_types)]
 pub struct init {
     mutexed_cache: Mutex<LruCache<String, Result<Regex, String>>>,
+    window: usize,
+    strict: bool,
 }
 
-const PRIV_ANCHOR: *const c_void = [0].as_ptr().cast::<c_void>();
+/// `priv_task` slot for response-body steps (`Direction::Fetch`/`Direction::Deliver`).
+const PRIV_ANCHOR_RESP: *const c_void = [0].as_ptr().cast::<c_void>();
+/// `priv_task` slot for request-body steps (`Direction::ReqFetch`/`Direction::ReqDeliver`).
+/// Kept separate from `PRIV_ANCHOR_RESP` so that registering both `replace_resp_body()` and
+/// `replace_req_body()` on the same transaction doesn't have one `Vxp::new()` call take the
+/// whole (request+response) step list and leave the other processor with nothing.
+const PRIV_ANCHOR_REQ: *const c_void = [1].as_ptr().cast::<c_void>();
 const NAME: &CStr = c"rers";
 
+/// Which `priv_task` slot holds the steps for `dir`.
+fn priv_anchor_for(dir: Direction) -> *const c_void {
+    match dir {
+        Direction::Fetch | Direction::Deliver => PRIV_ANCHOR_RESP,
+        Direction::ReqFetch | Direction::ReqDeliver => PRIV_ANCHOR_REQ,
+    }
+}
+
 pub struct Captures<'a> {
     caps: regex::bytes::Captures<'a>,
     #[allow(dead_code)]
@@ -279,21 +526,153 @@ pub struct Captures<'a> {
     slice: Option<&'a [u8]>,
 }
 
+pub struct Splits<'a> {
+    pieces: Vec<&'a [u8]>,
+    #[allow(dead_code)]
+    text: Option<Vec<u8>>,
+}
+
+/// Tags a registered `Step` with both which body it applies to (response vs. request) and
+/// which processing trait runs it (`FetchProcessor::pull`, reading bytes in, vs.
+/// `DeliveryProcessor::push`, writing bytes out). Request and response steps also live in
+/// separate `priv_task` slots (see `priv_anchor_for`), so `replace_resp_body()` and
+/// `replace_req_body()` rules coexist on the same transaction without one set consuming the
+/// other's: within a side, the `Fetch`/`Deliver` (or `ReqFetch`/`ReqDeliver`) distinction is
+/// what then lets `Vxp::push_dir`/`Vxp::pull_dir` pick only the steps for the phase actually
+/// running.
+#[derive(Clone, Copy, PartialEq)]
 enum Direction {
+    /// Response body ingested from the backend (`beresp.filters`, `pull`).
     Fetch,
+    /// Response body delivered to the client (`resp.filters`, `push`).
     Deliver,
+    /// Request body ingested from the client (`req.filters`, `pull`).
+    ReqFetch,
+    /// Request body delivered to the backend (`bereq.filters`, `push`).
+    ReqDeliver,
+}
+
+/// A registered regex/substitute pair, tagged with the direction it applies to.
+///
+/// `buffer_full` is set for patterns that rely on buffer-relative anchors (`^`, `$`, `\A`,
+/// `\z`, or the `(?m)` flag): splitting the haystack at an arbitrary chunk boundary can change
+/// where these match, so such steps force the whole body to be buffered before running the
+/// replacement, instead of the bounded-memory streaming path used otherwise. `window` is the
+/// number of trailing bytes of the streaming carry buffer held back in case a match straddles
+/// a chunk boundary. See `Vxp::requires_full_buffer` for the other cases that force full
+/// buffering: an encoded body, more than one streaming-safe step chained on the same
+/// direction, or a step with a non-zero `limit`.
+struct Step {
+    dir: Direction,
+    re: Regex,
+    sub: String,
+    limit: usize,
+    window: usize,
+    buffer_full: bool,
+    fail_on_bad_encoding: bool,
+}
+
+/// The `Content-Encoding` a body was served with. Recognized codecs are transparently
+/// decompressed before running regex replacement and re-compressed afterwards; `Unsupported`
+/// covers anything else (unknown codecs, or several chained together, e.g. `gzip, br`).
+#[derive(Clone, Copy, PartialEq)]
+enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+    Unsupported,
+}
+
+impl Encoding {
+    fn from_header(value: &str) -> Self {
+        let value = value.trim();
+        if value.is_empty() || value.eq_ignore_ascii_case("identity") {
+            return Encoding::Identity;
+        }
+        // several encodings stacked (e.g. "gzip, br") aren't handled, bail out early
+        if value.contains(',') {
+            return Encoding::Unsupported;
+        }
+        match value.to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Encoding::Gzip,
+            "deflate" => Encoding::Deflate,
+            "br" => Encoding::Brotli,
+            _ => Encoding::Unsupported,
+        }
+    }
+}
+
+/// Decompress `body` according to `encoding`. Unsupported/identity encodings are passed
+/// through untouched.
+fn decode(encoding: Encoding, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match encoding {
+        Encoding::Identity | Encoding::Unsupported => return Ok(body.to_vec()),
+        Encoding::Gzip => flate2::read::GzDecoder::new(body).read_to_end(&mut out)?,
+        Encoding::Deflate => flate2::read::DeflateDecoder::new(body).read_to_end(&mut out)?,
+        Encoding::Brotli => brotli::Decompressor::new(body, 4096).read_to_end(&mut out)?,
+    };
+    Ok(out)
+}
+
+/// Re-compress `body` according to `encoding`, mirroring `decode`. Unsupported/identity
+/// encodings are passed through untouched.
+fn encode(encoding: Encoding, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Identity | Encoding::Unsupported => Ok(body.to_vec()),
+        Encoding::Gzip => {
+            let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            enc.write_all(body)?;
+            enc.finish()
+        }
+        Encoding::Deflate => {
+            let mut enc =
+                flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            enc.write_all(body)?;
+            enc.finish()
+        }
+        Encoding::Brotli => {
+            let mut out = Vec::new();
+            brotli::CompressorWriter::new(&mut out, 4096, 5, 22).write_all(body)?;
+            Ok(out)
+        }
+    }
 }
 
 struct Vxp {
-    steps: Vec<(Direction, Regex, String, usize)>,
+    steps: Vec<Step>,
+    /// Full-body accumulator for `buffer_full` steps (and for any encoded body, see
+    /// `Vxp::requires_full_buffer`); also doubles as the queue of already-replaced bytes
+    /// waiting to be pulled out in the streaming fetch path.
     body: Vec<u8>,
+    /// Bounded-memory carry buffer for the streaming path: holds bytes not yet known to be
+    /// safe to replace and emit.
+    carry: Vec<u8>,
     sent: Option<usize>,
+    out_sent: usize,
+    finished: bool,
+    /// `Content-Encoding` the body was served with, detected once when the processor starts.
+    encoding: Encoding,
+    /// `true` when this instance is processing a request body (`req.filters`/`bereq.filters`)
+    /// rather than a response body (`beresp.filters`/`resp.filters`), detected once when the
+    /// processor starts. Picks which pair of `Direction` variants `push`/`pull` honor.
+    req_body: bool,
 }
 
 impl Vxp {
-    fn new(vrt_ctx: &Ctx) -> InitResult<Vxp> {
+    /// `req_body` picks which `priv_task` slot to pull steps from: request-body steps
+    /// (`replace_req_body()`) and response-body steps (`replace_resp_body()`) are kept in
+    /// separate slots so that neither processor consumes the other's steps (see
+    /// `priv_anchor_for`).
+    fn new(vrt_ctx: &Ctx, req_body: bool) -> InitResult<Vxp> {
+        let anchor = if req_body {
+            PRIV_ANCHOR_REQ
+        } else {
+            PRIV_ANCHOR_RESP
+        };
         unsafe {
-            match ffi::VRT_priv_task_get(vrt_ctx.raw, PRIV_ANCHOR)
+            match ffi::VRT_priv_task_get(vrt_ctx.raw, anchor)
                 .as_mut()
                 .and_then(|p| (*p).take::<Vxp>())
             {
@@ -302,6 +681,100 @@ impl Vxp {
             }
         }
     }
+
+    /// `true` if any step registered for `dir` requires the whole body to be buffered before
+    /// it can be applied: because the pattern itself demands it, because the body needs to be
+    /// decompressed as a whole before regex can run against it, because more than one
+    /// streaming-safe step is registered for `dir`, or because a step has a non-zero `limit`.
+    /// The streaming path chains steps by running step N+1 over step N's already-emitted
+    /// output (see `apply_steps`), so a match that step N+1 would form across the
+    /// emitted/retained boundary is lost; buffering the whole body avoids that divergence
+    /// whenever there's more than one step to chain. A non-zero `limit` needs the same
+    /// treatment: `apply_steps` runs each emitted chunk through `Regex::replacen` independently,
+    /// so a bounded `limit` would otherwise be applied per chunk instead of once over the
+    /// whole body.
+    fn requires_full_buffer(&self, dir: Direction) -> bool {
+        if self.encoding != Encoding::Identity {
+            return true;
+        }
+        let mut streaming_steps = 0;
+        for step in self.steps.iter().filter(|s| s.dir == dir) {
+            if step.buffer_full || step.limit != 0 {
+                return true;
+            }
+            streaming_steps += 1;
+        }
+        streaming_steps > 1
+    }
+
+    /// The match window to use for `dir`: the largest window requested by any streaming-safe
+    /// step registered for that direction, or `0` if there are none.
+    fn window_for(&self, dir: Direction) -> usize {
+        self.steps
+            .iter()
+            .filter(|s| s.dir == dir && !s.buffer_full)
+            .map(|s| s.window)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// How many leading bytes of `self.carry` are safe to replace and emit right now. Bytes
+    /// within `window` of the end are never safe, since a match could still be extended by
+    /// data we haven't received yet; on top of that, any match that reaches into that trailing
+    /// window pulls the cutoff back to its start, so it stays intact in `carry` for next time.
+    fn safe_cut(&self, dir: Direction) -> usize {
+        let mut cut = self.carry.len().saturating_sub(self.window_for(dir));
+        for step in &self.steps {
+            if step.dir != dir || step.buffer_full {
+                continue;
+            }
+            for m in step.re.find_iter(&self.carry) {
+                if m.end() > cut {
+                    cut = cut.min(m.start());
+                }
+            }
+        }
+        cut
+    }
+
+    /// Run every streaming-safe step registered for `dir` over the first `cut` bytes of
+    /// `self.carry`, draining them out and returning the replaced result.
+    fn apply_steps(&mut self, dir: Direction, cut: usize) -> Vec<u8> {
+        let chunk: Vec<u8> = self.carry.drain(..cut).collect();
+        let mut replaced = Cow::from(&chunk);
+        for step in &self.steps {
+            if step.dir != dir || step.buffer_full {
+                continue;
+            }
+            // ignore the `Cow::Borrowed` case, it means nothing changed
+            if let Cow::Owned(s) = step.re.replacen(&replaced, step.limit, step.sub.as_bytes()) {
+                replaced = Cow::from(s);
+            }
+        }
+        replaced.into_owned()
+    }
+
+    /// The `Direction` `push()` (`DeliveryProcessor`) should honor for this instance: request
+    /// steps when forwarding a request body to the backend, response steps when delivering a
+    /// response to the client.
+    fn push_dir(&self) -> Direction {
+        if self.req_body {
+            Direction::ReqDeliver
+        } else {
+            Direction::Deliver
+        }
+    }
+
+    /// The `Direction` `pull()` (`FetchProcessor`) should honor for this instance: request
+    /// steps when ingesting a body from the client, response steps when fetching one from the
+    /// backend.
+    fn pull_dir(&self) -> Direction {
+        if self.req_body {
+            Direction::ReqFetch
+        } else {
+            Direction::Fetch
+        }
+    }
 }
 
 impl DeliveryProcessor for Vxp {
@@ -310,6 +783,18 @@ impl DeliveryProcessor for Vxp {
     }
 
     fn new(vrt_ctx: &mut Ctx, vdp_ctx: &mut DeliveryProcCtx) -> InitResult<Vxp> {
+        // delivering a response to the client has `http_resp`; delivering a request to the
+        // backend only has `http_bereq`
+        let req_body = vrt_ctx.http_resp.is_none();
+
+        let encoding = vrt_ctx
+            .http_resp
+            .as_ref()
+            .or(vrt_ctx.http_bereq.as_ref())
+            .and_then(|h| h.header("Content-Encoding"))
+            .map(Encoding::from_header)
+            .unwrap_or(Encoding::Identity);
+
         unsafe {
             let mut rm_cl = false;
             if vrt_ctx.raw.bo.as_ref().is_some() {
@@ -332,26 +817,77 @@ impl DeliveryProcessor for Vxp {
             }
         }
 
-        Vxp::new(vrt_ctx)
+        let mut result = Vxp::new(vrt_ctx, req_body);
+        if let InitResult::Ok(ref mut vxp) = result {
+            if encoding == Encoding::Unsupported {
+                if vxp.steps.iter().any(|s| s.fail_on_bad_encoding) {
+                    vrt_ctx.fail("rers: unsupported Content-Encoding for body replacement");
+                    return InitResult::Err(
+                        "rers: unsupported Content-Encoding for body replacement".into(),
+                    );
+                }
+                vrt_ctx.log(
+                    LogTag::VclError,
+                    "rers: unsupported Content-Encoding, leaving body untouched",
+                );
+            }
+            vxp.encoding = encoding;
+            vxp.req_body = req_body;
+        }
+        result
     }
 
     fn push(&mut self, ctx: &mut DeliveryProcCtx, act: VdpAction, buf: &[u8]) -> PushResult {
-        self.body.extend_from_slice(buf);
+        let dir = self.push_dir();
+        if self.requires_full_buffer(dir) {
+            self.body.extend_from_slice(buf);
 
-        if !matches!(act, VdpAction::End) {
-            return PushResult::Ok;
-        }
-        let mut replaced_body = Cow::from(&self.body);
-        for (dir, re, sub, limit) in &self.steps {
-            if !matches!(dir, Direction::Deliver) {
-                continue;
+            if !matches!(act, VdpAction::End) {
+                return PushResult::Ok;
             }
-            // ignore the `Cow::Borrowed` case, it means nothing changed
-            if let Cow::Owned(s) = re.replacen(&replaced_body, *limit, sub.as_bytes()) {
-                replaced_body = Cow::from(s);
+            // if the body can't be decoded (e.g. it wasn't actually encoded as advertised),
+            // forward it untouched rather than corrupting it
+            let Ok(decoded) = decode(self.encoding, &self.body) else {
+                return ctx.push(act, &self.body);
+            };
+            let mut replaced_body = Cow::from(&decoded);
+            // an unsupported/unrecognized encoding was already logged (and, unless
+            // `fail_on_bad_encoding`, tolerated) in `new()`; running the steps here would mean
+            // matching against the still-compressed bytes, so leave the body untouched instead
+            if self.encoding != Encoding::Unsupported {
+                for step in &self.steps {
+                    if step.dir != dir {
+                        continue;
+                    }
+                    // ignore the `Cow::Borrowed` case, it means nothing changed
+                    if let Cow::Owned(s) =
+                        step.re
+                            .replacen(&replaced_body, step.limit, step.sub.as_bytes())
+                    {
+                        replaced_body = Cow::from(s);
+                    }
+                }
+            }
+            let Ok(encoded) = encode(self.encoding, &replaced_body) else {
+                return ctx.push(act, &self.body);
+            };
+            return ctx.push(act, &encoded);
+        }
+
+        // streaming path: only commit the prefix of `carry` that's known not to overlap a
+        // not-yet-received match, keeping memory bounded by the configured window
+        self.carry.extend_from_slice(buf);
+        if !matches!(act, VdpAction::End) {
+            let cut = self.safe_cut(dir);
+            if cut == 0 {
+                return PushResult::Ok;
             }
+            let emitted = self.apply_steps(dir, cut);
+            return ctx.push(act, &emitted);
         }
-        ctx.push(act, &replaced_body)
+        let cut = self.carry.len();
+        let emitted = self.apply_steps(dir, cut);
+        ctx.push(act, &emitted)
     }
 }
 
@@ -361,50 +897,134 @@ impl FetchProcessor for Vxp {
     }
 
     fn new(vrt_ctx: &mut Ctx, _: &mut FetchProcCtx) -> InitResult<Self> {
+        // ingesting a response from the backend has `http_beresp`; ingesting a request body
+        // from the client only has `http_req`
+        let req_body = vrt_ctx.http_beresp.is_none();
+
+        let encoding = vrt_ctx
+            .http_beresp
+            .as_ref()
+            .or(vrt_ctx.http_req.as_ref())
+            .and_then(|h| h.header("Content-Encoding"))
+            .map(Encoding::from_header)
+            .unwrap_or(Encoding::Identity);
+
         // we don't know how/if the body will be modified, so we nuke the content-length
-        if let Some(headers) = vrt_ctx.http_beresp.as_mut() {
+        if let Some(headers) = vrt_ctx.http_beresp.as_mut().or(vrt_ctx.http_req.as_mut()) {
             headers.unset_header("Content-Length");
         }
 
-        Vxp::new(vrt_ctx)
+        let mut result = Vxp::new(vrt_ctx, req_body);
+        if let InitResult::Ok(ref mut vxp) = result {
+            if encoding == Encoding::Unsupported {
+                if vxp.steps.iter().any(|s| s.fail_on_bad_encoding) {
+                    vrt_ctx.fail("rers: unsupported Content-Encoding for body replacement");
+                    return InitResult::Err(
+                        "rers: unsupported Content-Encoding for body replacement".into(),
+                    );
+                }
+                vrt_ctx.log(
+                    LogTag::VclError,
+                    "rers: unsupported Content-Encoding, leaving body untouched",
+                );
+            }
+            vxp.encoding = encoding;
+            vxp.req_body = req_body;
+        }
+        result
     }
 
     fn pull(&mut self, ctx: &mut FetchProcCtx, buf: &mut [u8]) -> PullResult {
-        // first pull everything, using buf to receive the initial data before extending our body vector
-        while self.sent.is_none() {
+        let dir = self.pull_dir();
+        if self.requires_full_buffer(dir) {
+            // first pull everything, using buf to receive the initial data before extending our body vector
+            while self.sent.is_none() {
+                match ctx.pull(buf) {
+                    PullResult::Err => return PullResult::Err,
+                    PullResult::Ok(sz) => {
+                        self.body.extend_from_slice(&buf[..sz]);
+                    }
+                    PullResult::End(sz) => {
+                        self.body.extend_from_slice(&buf[..sz]);
+                        // same trick as for VDP, we run all our regex, but this time we'll revert the
+                        // body back into a vector for the next times we are called
+                        //
+                        // if the body can't be decoded (e.g. it wasn't actually encoded as
+                        // advertised), fall back to leaving it untouched rather than corrupting it
+                        let Ok(decoded) = decode(self.encoding, &self.body) else {
+                            self.sent = Some(0);
+                            continue;
+                        };
+                        let mut replaced_body = Cow::from(&decoded);
+                        // see the matching comment in `DeliveryProcessor::push`: an unsupported
+                        // encoding means these bytes are still compressed, so skip the steps
+                        // rather than matching against (and corrupting) the compressed body
+                        if self.encoding != Encoding::Unsupported {
+                            for step in &self.steps {
+                                if step.dir != dir {
+                                    continue;
+                                }
+                                // ignore the `Cow::Borrowed` case, it means nothing changed
+                                if let Cow::Owned(s) =
+                                    step.re
+                                        .replacen(&replaced_body, step.limit, step.sub.as_bytes())
+                                {
+                                    replaced_body = Cow::from(s);
+                                }
+                            }
+                        }
+                        self.body = encode(self.encoding, &replaced_body)
+                            .unwrap_or_else(|_| replaced_body.into_owned());
+                        self.sent = Some(0);
+                    }
+                }
+            }
+            // the body is completely in memory and fully transformed, we just need to copy whatever we
+            // can into buf, and keep track of the data already transferred
+            let mut out = self.sent.unwrap();
+            assert!(out <= self.body.len());
+            let len = std::cmp::min(buf.len(), self.body.len() - out);
+            buf[..len].copy_from_slice(&self.body[out..(out + len)]);
+            out += len;
+            self.sent = Some(out);
+            return if out == self.body.len() {
+                PullResult::End(len)
+            } else {
+                PullResult::Ok(len)
+            };
+        }
+
+        // streaming path: pull upstream in chunks, keep only a bounded `carry` buffer around,
+        // and queue already-replaced bytes in `body` until the caller drains them
+        while self.out_sent >= self.body.len() && !self.finished {
             match ctx.pull(buf) {
                 PullResult::Err => return PullResult::Err,
                 PullResult::Ok(sz) => {
-                    self.body.extend_from_slice(&buf[..sz]);
+                    self.carry.extend_from_slice(&buf[..sz]);
+                    let cut = self.safe_cut(dir);
+                    if cut > 0 {
+                        let emitted = self.apply_steps(dir, cut);
+                        self.body.extend_from_slice(&emitted);
+                    }
                 }
                 PullResult::End(sz) => {
-                    self.body.extend_from_slice(&buf[..sz]);
-                    // same trick as for VDP, we run all our regex, but this time we'll revert the
-                    // body back into a vector for the next times we are called
-                    let mut replaced_body = Cow::from(&self.body);
-                    for (dir, re, sub, limit) in &self.steps {
-                        if !matches!(dir, Direction::Fetch) {
-                            continue;
-                        }
-                        // ignore the `Cow::Borrowed` case, it means nothing changed
-                        if let Cow::Owned(s) = re.replacen(&replaced_body, *limit, sub.as_bytes()) {
-                            replaced_body = Cow::from(s);
-                        }
-                    }
-                    self.body = replaced_body.into_owned();
-                    self.sent = Some(0);
+                    self.carry.extend_from_slice(&buf[..sz]);
+                    let cut = self.carry.len();
+                    let emitted = self.apply_steps(dir, cut);
+                    self.body.extend_from_slice(&emitted);
+                    self.finished = true;
                 }
             }
         }
-        // the body is completely in memory and fully transformed, we just need to copy whatever we
-        // can into buf, and keep track of the data already transferred
-        let mut out = self.sent.unwrap();
-        assert!(out <= self.body.len());
-        let len = std::cmp::min(buf.len(), self.body.len() - out);
-        buf[..len].copy_from_slice(&self.body[out..(out + len)]);
-        out += len;
-        self.sent = Some(out);
-        if out == self.body.len() {
+
+        let avail = self.body.len() - self.out_sent;
+        let len = std::cmp::min(buf.len(), avail);
+        buf[..len].copy_from_slice(&self.body[self.out_sent..(self.out_sent + len)]);
+        self.out_sent += len;
+        // drop what's already been delivered so `body` doesn't grow with the whole response
+        self.body.drain(..self.out_sent);
+        self.out_sent = 0;
+        if self.finished && self.body.is_empty() {
             PullResult::End(len)
         } else {
             PullResult::Ok(len)
@@ -418,6 +1038,28 @@ static PRIV_VXP_METHODS: vmod_priv_methods = vmod_priv_methods {
     fini: Some(vmod_priv::on_fini::<Vxp>),
 };
 
+/// `true` if `res` contains a buffer-relative anchor (`^`, `$`, `\A`, `\z`) or the `(?m)`
+/// flag. Such patterns can match differently depending on where a streamed body happens to be
+/// cut into chunks, so callers use this to fall back to full buffering for them.
+fn pattern_needs_full_buffer(res: &str) -> bool {
+    if res.contains("(?m)") || res.contains("\\A") || res.contains("\\z") {
+        return true;
+    }
+    let mut escaped = false;
+    for b in res.bytes() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match b {
+            b'\\' => escaped = true,
+            b'^' | b'$' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
 /// Convert an i64 to a `usize`, clamping it between zero to the maximum value of usize
 pub(crate) fn clamp_i64_to_usize(value: i64) -> usize {
     // If i64 is bigger than usize, return usize::MAX, otherwise any positive i64 will fit within usize